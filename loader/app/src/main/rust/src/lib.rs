@@ -2,13 +2,15 @@ use jni::JNIEnv;
 use jni::objects::{JClass, JObject, JString, JValue, JObjectArray, JByteArray};
 use jni::sys::{jint, JNI_VERSION_1_6};
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce
+    aead::{generic_array::GenericArray, stream::DecryptorBE32, KeyInit},
+    Aes256Gcm,
 };
+use chacha20poly1305::ChaCha20Poly1305;
 use std::os::raw::c_void;
 use std::fs::File;
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
 use zip::ZipArchive;
 
 mod config;
@@ -17,6 +19,7 @@ mod strings_config;
 mod obfuscate;
 use config::{get_aes_key, PAYLOAD_HASH, EXPECTED_SIGNATURE_HASH};
 use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
 
 #[macro_use]
 extern crate log;
@@ -198,7 +201,7 @@ pub extern "system" fn Java_com_kapp_shell_ShellApplication_nativeLoadDex<'local
         // std::process::exit(1);
     }
 
-    let payload = match extract_payload(&apk_path, true) {
+    let payload = match extract_payload(&apk_path, true, true) {
         Ok(payload) => payload,
         Err(e) => {
             error!("Payload integrity failed: {:?}", e);
@@ -263,7 +266,7 @@ pub extern "system" fn Java_com_kapp_shell_ShellApplication_nativeLoadDexWithApp
     // 2. Verify Integrity (Skip for now if we only have app_info, or pass JObject::null())
     // For now, we skip signature check here to avoid complexity of getting PM from app_info.
     // It will be verified in nativeLoadDex or BootstrapProvider anyway.
-    let payload = match extract_payload(&apk_path, true) {
+    let payload = match extract_payload(&apk_path, true, true) {
         Ok(payload) => payload,
         Err(e) => {
             error!("Payload integrity failed: {:?}", e);
@@ -325,7 +328,7 @@ pub extern "system" fn Java_com_kapp_shell_BootstrapProvider_nativeLoadDex<'loca
         // std::process::exit(1);
     }
 
-    let payload = match extract_payload(&apk_path, true) {
+    let payload = match extract_payload(&apk_path, true, true) {
         Ok(payload) => payload,
         Err(e) => {
             error!("Payload integrity failed: {:?}", e);
@@ -351,19 +354,30 @@ fn load_dex_core(
     payload: &[(String, Vec<u8>)],
     sdk_int: jint,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // 0. Unpack any embedded multidex jar/apk entries into the flat classesN.dex
+    // form the rest of this pipeline expects.
+    let payload = expand_multidex_containers(payload);
+    let payload = payload.as_slice();
+
     // 1. Extract Assets
     extract_assets_core(data_path, payload)?;
 
     // 2. Load DEX and Libs
-    // NOTE:
-    // We intentionally use file-landing on all SDKs. In-memory mode can trigger
-    // "Attempt to register dex file ... with multiple class loaders" for some
-    // multi-process/provider startup sequences.
+    // Prefer in-memory loading on API 26+ (InMemoryDexClassLoader) so extracted dex
+    // bytes never touch disk. Some multi-process/provider startup sequences have been
+    // seen to reject InMemoryDexClassLoader (class missing, or ART refusing to
+    // register the same bytes twice); fall back to file-landing in that case.
     if sdk_int >= 26 {
-        info!(
-            "load_dex_core: using file-landing mode on SDK {} for class-loader compatibility",
-            sdk_int
-        );
+        match load_in_memory(env, cache_path, class_loader, payload) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!(
+                    "load_dex_core: in-memory dex loading failed on SDK {}, falling back to file-landing: {:?}",
+                    sdk_int, e
+                );
+                let _ = env.exception_clear();
+            }
+        }
     }
     load_file_landing(env, cache_path, class_loader, payload).map_err(|e| e.into())
 }
@@ -452,7 +466,7 @@ fn verify_integrity(
     Ok(())
 }
 
-fn extract_payload(path: &str, verify: bool) -> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
+fn extract_payload(path: &str, verify: bool, verify_dex: bool) -> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
     debug!("{} {}", s!(strings_config::MSG_OPEN_APK).replace("{}", ""), path);
     let apk_file = File::open(path)?;
     let mut apk_zip = ZipArchive::new(apk_file)?;
@@ -469,8 +483,8 @@ fn extract_payload(path: &str, verify: bool) -> Result<Vec<(String, Vec<u8>)>, B
         if hash.as_slice() != PAYLOAD_HASH {
             // Check if it's all zeros (empty/dummy config)
             if PAYLOAD_HASH != [0u8; 32] {
-                error!("Payload hash mismatch! Expected: {}, Actual: {}", 
-                    hex::encode(PAYLOAD_HASH), 
+                error!("Payload hash mismatch! Expected: {}, Actual: {}",
+                    hex::encode(PAYLOAD_HASH),
                     hex::encode(hash));
                 return Err("Payload integrity check failed".into());
             } else {
@@ -479,34 +493,194 @@ fn extract_payload(path: &str, verify: bool) -> Result<Vec<(String, Vec<u8>)>, B
         }
     }
 
-    decrypt_payload(&encrypted_data, &get_aes_key())
+    decrypt_payload(&encrypted_data, &get_aes_key(), verify_dex)
+}
+
+const DEX_MAGIC_PREFIX: &[u8; 4] = b"dex\n";
+const DEX_HEADER_LEN: usize = 0x70;
+
+fn dex_adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = (a + byte as u32) % MOD_ADLER;
+        b = (b + a) % MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// Validates a decrypted dex blob's header before it is handed to ART: the
+/// `dex\n` magic prefix, the Adler-32 checksum at offset 8 (computed over
+/// bytes [12..end]), and the SHA-1 signature at offset 12 (computed over
+/// bytes [32..end]). A corrupt or truncated dex should be rejected here with
+/// a clear error rather than crashing ART during class linking.
+fn verify_dex_integrity(data: &[u8]) -> Result<(), String> {
+    if data.len() < DEX_HEADER_LEN {
+        return Err(format!("dex too small for header ({} bytes)", data.len()));
+    }
+    if &data[0..4] != DEX_MAGIC_PREFIX {
+        return Err("bad dex magic".to_string());
+    }
+
+    let stored_checksum = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    let computed_checksum = dex_adler32(&data[12..]);
+    if stored_checksum != computed_checksum {
+        return Err(format!(
+            "checksum mismatch (header {:#010x}, computed {:#010x})",
+            stored_checksum, computed_checksum
+        ));
+    }
+
+    let stored_signature = &data[12..32];
+    use sha1::Digest as _;
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(&data[32..]);
+    let computed_signature = hasher.finalize();
+    if computed_signature.as_slice() != stored_signature {
+        return Err("SHA-1 signature mismatch".to_string());
+    }
+
+    Ok(())
+}
+
+/// Blob layout version the packer's `repack_target_with_bootstrap` writes into the footer.
+/// Bumped whenever the footer or metadata record shape changes, so an old
+/// loader fails the version check instead of misparsing a newer blob.
+const PAYLOAD_FORMAT_VERSION: u8 = 4;
+
+/// Authentication tag length appended to every ciphertext chunk by both
+/// AES-256-GCM and ChaCha20-Poly1305 (see the packer's matching constant).
+const STREAM_TAG_LEN: u64 = 16;
+
+/// Re-derives the per-entry subkey the packer used to encrypt `entry_name`:
+/// `PRK = HKDF-Extract(salt, master_key)`, then `entry_key =
+/// HKDF-Expand(PRK, info = entry_name, L = 32)`. See the packer's
+/// `derive_entry_key` for the encrypting side of this scheme.
+fn derive_entry_key(salt: &[u8; 16], master_key: &[u8; 32], entry_name: &str) -> [u8; 32] {
+    let hkdf = hkdf::Hkdf::<Sha256>::new(Some(salt), master_key);
+    let mut entry_key = [0u8; 32];
+    hkdf.expand(entry_name.as_bytes(), &mut entry_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    entry_key
+}
+
+/// Re-derives the key the packer used to BLAKE3-keyed-hash the blob's
+/// ciphertext-plus-metadata buffer. See the packer's `derive_integrity_key`.
+fn derive_integrity_key(salt: &[u8; 16], master_key: &[u8; 32]) -> [u8; 32] {
+    let hkdf = hkdf::Hkdf::<Sha256>::new(Some(salt), master_key);
+    let mut integrity_key = [0u8; 32];
+    hkdf.expand(b"blob-integrity", &mut integrity_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    integrity_key
+}
+
+/// STREAM-construction (Rogaway) decryptor counterpart to the packer's
+/// `StreamEncryptor`: the 12-byte AEAD nonce is the entry's 7-byte stored
+/// prefix plus an internal 32-bit big-endian chunk counter and a 1-byte
+/// "last chunk" flag, so chunks must be decrypted in order starting from 0.
+enum StreamDecryptor {
+    Aes256Gcm(DecryptorBE32<Aes256Gcm>),
+    Chacha20Poly1305(DecryptorBE32<ChaCha20Poly1305>),
+}
+
+impl StreamDecryptor {
+    fn new(cipher_id: u8, entry_key: &[u8; 32], nonce_prefix: &[u8; 7]) -> Result<Self, String> {
+        let nonce_prefix = GenericArray::from_slice(nonce_prefix);
+        match cipher_id {
+            0 => Ok(StreamDecryptor::Aes256Gcm(DecryptorBE32::from_aead(
+                Aes256Gcm::new(entry_key.into()),
+                nonce_prefix,
+            ))),
+            1 => Ok(StreamDecryptor::Chacha20Poly1305(DecryptorBE32::from_aead(
+                ChaCha20Poly1305::new(entry_key.into()),
+                nonce_prefix,
+            ))),
+            other => Err(format!("Unknown payload cipher id {}", other)),
+        }
+    }
+
+    fn decrypt_next(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            StreamDecryptor::Aes256Gcm(dec) => dec
+                .decrypt_next(chunk)
+                .map_err(|e| format!("stream decryption failure: {:?}", e)),
+            StreamDecryptor::Chacha20Poly1305(dec) => dec
+                .decrypt_next(chunk)
+                .map_err(|e| format!("stream decryption failure: {:?}", e)),
+        }
+    }
+
+    fn decrypt_last(self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            StreamDecryptor::Aes256Gcm(dec) => dec
+                .decrypt_last(chunk)
+                .map_err(|e| format!("stream decryption failure: {:?}", e)),
+            StreamDecryptor::Chacha20Poly1305(dec) => dec
+                .decrypt_last(chunk)
+                .map_err(|e| format!("stream decryption failure: {:?}", e)),
+        }
+    }
 }
 
 fn decrypt_payload(
     encrypted_data: &[u8],
     key: &[u8; 32],
+    verify_dex: bool,
 ) -> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
     let mut file = std::io::Cursor::new(encrypted_data);
     let file_len = encrypted_data.len() as u64;
 
-    // Footer: [Metadata Size (4)] [Magic "SHELL" (5)]
-    let footer_len = 9;
+    // Footer: [Metadata Size (4)] [HKDF Salt (16)] [Cipher Id (1)] [Format Version (1)]
+    //         [BLAKE3 Digest (32)] [Magic "SHELL" (5)]
+    let footer_len = 59;
     if file_len < footer_len {
         return Err("Payload too small".into());
     }
 
     file.seek(SeekFrom::End(-(footer_len as i64)))?;
-    let mut footer = [0u8; 9];
+    let mut footer = [0u8; 59];
     file.read_exact(&mut footer)?;
 
-    let magic = &footer[4..9];
+    let magic = &footer[54..59];
     if magic != s!(strings_config::MAGIC_SHELL).as_bytes() {
         return Err(s!(strings_config::ERR_NO_PAYLOAD).into());
     }
 
+    let build_salt: [u8; 16] = footer[4..20].try_into().unwrap();
+    let cipher_id = footer[20];
+    let format_version = footer[21];
+    if format_version != PAYLOAD_FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported payload format version {} (expected {})",
+            format_version, PAYLOAD_FORMAT_VERSION
+        )
+        .into());
+    }
+    let stored_digest: [u8; 32] = footer[22..54].try_into().unwrap();
     let metadata_size = u32::from_le_bytes(footer[0..4].try_into()?) as u64;
-    
-    // Metadata Block: [N (4)] + [ [NameLen(2)] [Name] [Size(4)] [IV(12)] ] * N
+
+    // The digest covers everything the packer wrote before the last 55 bytes of the
+    // footer (the ciphertext region, the metadata table, and the metadata size itself),
+    // so reordering entries, renaming one, or truncating the table is caught here
+    // before any offset in the (still unverified) metadata is trusted.
+    let digested_len = file_len
+        .checked_sub(55)
+        .ok_or("Invalid footer length")?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut digested = vec![0u8; digested_len as usize];
+    file.read_exact(&mut digested)?;
+
+    let integrity_key = derive_integrity_key(&build_salt, key);
+    let computed_digest = *blake3::keyed_hash(&integrity_key, &digested).as_bytes();
+    if computed_digest.ct_eq(&stored_digest).unwrap_u8() != 1 {
+        return Err("Payload integrity digest mismatch".into());
+    }
+
+    // Metadata Block: [N (4)] + [ [NameLen(2)] [Name] [Offset(8)] [PlaintextSize(8)]
+    // [ChunkSize(4)] [ChunkCount(4)] [NoncePrefix(7)] [Compression(1)] ] * N
+    // Offsets are absolute positions into the ciphertext region, so any entry can be
+    // located and decrypted on its own without summing the lengths of the entries before it.
     let metadata_start = file_len
         .checked_sub(footer_len as u64)
         .and_then(|v| v.checked_sub(metadata_size))
@@ -522,106 +696,174 @@ fn decrypt_payload(
     let num_files = u32::from_le_bytes(n_bytes);
 
     let mut entries = Vec::new();
-    let mut total_encrypted_size = 0;
 
     for _ in 0..num_files {
         let mut name_len_bytes = [0u8; 2];
         cursor.read_exact(&mut name_len_bytes)?;
         let name_len = u16::from_le_bytes(name_len_bytes) as usize;
-        
+
         let mut name_bytes = vec![0u8; name_len];
         cursor.read_exact(&mut name_bytes)?;
         let name = String::from_utf8(name_bytes)?;
 
-        let mut size_bytes = [0u8; 4];
-        cursor.read_exact(&mut size_bytes)?;
-        let size = u32::from_le_bytes(size_bytes);
-        
-        let mut iv = [0u8; 12];
-        cursor.read_exact(&mut iv)?;
-        
-        entries.push((name, size, iv));
-        total_encrypted_size += size as u64;
-    }
+        let mut offset_bytes = [0u8; 8];
+        cursor.read_exact(&mut offset_bytes)?;
+        let offset = u64::from_le_bytes(offset_bytes);
 
-    // Read Payloads
-    let payload_start = metadata_start
-        .checked_sub(total_encrypted_size)
-        .ok_or("Invalid payload offset")?;
+        let mut plaintext_size_bytes = [0u8; 8];
+        cursor.read_exact(&mut plaintext_size_bytes)?;
+        let plaintext_size = u64::from_le_bytes(plaintext_size_bytes);
 
-    file.seek(SeekFrom::Start(payload_start))?;
-    
-    let cipher = Aes256Gcm::new(key.into());
-    let mut results = Vec::new();
+        let mut chunk_size_bytes = [0u8; 4];
+        cursor.read_exact(&mut chunk_size_bytes)?;
+        let chunk_size = u32::from_le_bytes(chunk_size_bytes) as u64;
 
-    for (name, size, iv) in entries {
-        let mut enc_buf = vec![0u8; size as usize];
-        file.read_exact(&mut enc_buf)?;
-        
-        let nonce = Nonce::from_slice(&iv);
-        let plaintext = cipher.decrypt(nonce, enc_buf.as_ref())
-            .map_err(|e| format!("Decryption failed for {}: {:?}", name, e))?;
-        
-        results.push((name, plaintext));
-    }
-    
-    Ok(results)
-}
+        let mut chunk_count_bytes = [0u8; 4];
+        cursor.read_exact(&mut chunk_count_bytes)?;
+        let chunk_count = u32::from_le_bytes(chunk_count_bytes);
 
-fn load_in_memory(env: &mut JNIEnv, cache_path: &str, target_loader: &JObject, file_list: &[(String, Vec<u8>)]) -> Result<(), jni::errors::Error> {
-    info!("load_in_memory called with {} items", file_list.len());
-    // 1. Separate DEXs and Libs
-    let mut dex_buffers = Vec::new();
-    let mut lib_buffers = Vec::new();
-    let current_abi = get_current_abi();
-    debug!("Current ABI: {}", current_abi);
+        let mut nonce_prefix = [0u8; 7];
+        cursor.read_exact(&mut nonce_prefix)?;
 
-    let lib_prefix = format!("lib/{}/", current_abi);
+        let mut compression_id = [0u8; 1];
+        cursor.read_exact(&mut compression_id)?;
 
-    for (name, data) in file_list {
-        if name.ends_with(".dex") {
-             dex_buffers.push(data);
-        } else if name.starts_with(&lib_prefix) && name.ends_with(".so") {
-             let filename = name.strip_prefix(&lib_prefix).unwrap_or(name);
-             lib_buffers.push((filename, data));
-        }
+        entries.push((name, offset, plaintext_size, chunk_size, chunk_count, nonce_prefix, compression_id[0]));
     }
 
-    debug!("Found {} DEXs and {} Libs for current ABI", dex_buffers.len(), lib_buffers.len());
+    // The ciphertext region starts at byte 0 of the blob, so each entry's stored
+    // offset is already an absolute position we can seek to directly.
+    let mut results = Vec::new();
+
+    for (name, offset, plaintext_size, chunk_size, chunk_count, nonce_prefix, compression_id) in entries {
+        let ciphertext_size = plaintext_size + STREAM_TAG_LEN * chunk_count as u64;
+        if offset + ciphertext_size > metadata_start {
+            return Err(format!("Entry '{}' offset out of bounds", name).into());
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        let entry_key = derive_entry_key(&build_salt, key, &name);
+        let mut decryptor = StreamDecryptor::new(cipher_id, &entry_key, &nonce_prefix)?;
+
+        // Chunks are decrypted in order starting from the stream counter at 0,
+        // one `chunk_size + STREAM_TAG_LEN`-byte ciphertext chunk at a time.
+        // Memory use is not bounded by the chunk size: the whole payload blob is
+        // already in memory (`encrypted_data`, plus the copy hashed for the
+        // digest above), and each entry's decrypted plaintext is accumulated in
+        // full, since every caller across the JNI boundary (`nativeLoadDex` et
+        // al.) takes one complete `Vec<u8>` per entry.
+        let full_chunk_ciphertext_len = (chunk_size + STREAM_TAG_LEN) as usize;
+        let mut decrypted = Vec::with_capacity(plaintext_size as usize);
+        let mut chunk_buf = vec![0u8; full_chunk_ciphertext_len];
+        for chunk_index in 0..chunk_count.saturating_sub(1) {
+            file.read_exact(&mut chunk_buf)?;
+            decrypted.extend_from_slice(
+                &decryptor
+                    .decrypt_next(&chunk_buf)
+                    .map_err(|e| format!("Decryption failed for {} (chunk {}): {}", name, chunk_index, e))?,
+            );
+        }
+        let last_chunk_len =
+            ciphertext_size as usize - full_chunk_ciphertext_len * chunk_count.saturating_sub(1) as usize;
+        let mut last_chunk = vec![0u8; last_chunk_len];
+        file.read_exact(&mut last_chunk)?;
+        decrypted.extend_from_slice(
+            &decryptor
+                .decrypt_last(&last_chunk)
+                .map_err(|e| format!("Decryption failed for {} (last chunk): {}", name, e))?,
+        );
 
-    // 2. Extract Libs to Cache
-    debug!("Extracting libs to cache...");
-    let libs_dir = format!("{}/native_libs", cache_path);
-    std::fs::create_dir_all(&libs_dir).unwrap_or(());
+        let plaintext = match compression_id {
+            0 => decrypted,
+            1 => {
+                let mut out = Vec::new();
+                xz2::read::XzDecoder::new(decrypted.as_slice()).read_to_end(&mut out)?;
+                out
+            }
+            2 => zstd::stream::decode_all(decrypted.as_slice())?,
+            other => return Err(format!("Unknown payload compression id {}", other).into()),
+        };
 
-    for (filename, data) in lib_buffers {
-        let lib_path = format!("{}/{}", libs_dir, filename);
-        debug!("Writing lib: {}", lib_path);
-        if let Ok(mut file) = File::create(&lib_path) {
-            let _ = file.write_all(data);
+        if verify_dex && name.ends_with(".dex") {
+            if let Err(e) = verify_dex_integrity(&plaintext) {
+                error!("Rejecting corrupt dex '{}': {}", name, e);
+                continue;
+            }
         }
+
+        results.push((name, plaintext));
     }
 
+    Ok(results)
+}
+
+/// Backing storage for dex bytes handed to ART as direct `ByteBuffer`s.
+///
+/// `InMemoryDexClassLoader` does not copy the buffer it is given, so the
+/// `Vec<u8>` underneath each direct `ByteBuffer` must outlive the loader
+/// itself. Entries are never removed; this process is expected to hold at
+/// most a handful of dex payloads for its whole lifetime.
+static LEAKED_DEX_BUFFERS: OnceLock<Mutex<Vec<Vec<u8>>>> = OnceLock::new();
+
+/// Moves `data` into a process-lifetime registry and returns a `'static`
+/// slice pointing at it, suitable for `JNIEnv::new_direct_byte_buffer`.
+fn retain_dex_buffer(data: Vec<u8>) -> &'static mut [u8] {
+    let registry = LEAKED_DEX_BUFFERS.get_or_init(|| Mutex::new(Vec::new()));
+    let mut guard = registry.lock().unwrap_or_else(|e| e.into_inner());
+    guard.push(data);
+    let stored = guard.last_mut().unwrap();
+    let ptr = stored.as_mut_ptr();
+    let len = stored.len();
+    // SAFETY: `stored` lives inside a Vec<Vec<u8>> that only ever grows via
+    // push and is never cleared, so its heap allocation is never freed or
+    // moved for the life of the process; `ptr`/`len` describe that stable
+    // allocation.
+    unsafe { std::slice::from_raw_parts_mut(ptr, len) }
+}
+
+fn load_in_memory(env: &mut JNIEnv, cache_path: &str, target_loader: &JObject, file_list: &[(String, Vec<u8>)]) -> Result<(), jni::errors::Error> {
+    info!("load_in_memory called with {} items", file_list.len());
+    // 1. Separate DEXs and Libs, loading classes.dex, classes2.dex, classes3.dex, ...
+    // in ascending index regardless of archive/metadata enumeration order, since
+    // InMemoryDexClassLoader resolves duplicate classes by array position.
+    let mut indexed_dex_buffers: Vec<(usize, &Vec<u8>)> = file_list
+        .iter()
+        .filter(|(name, _)| name.ends_with(".dex"))
+        .filter_map(|(name, data)| match multidex_index(name) {
+            Some(index) => Some((index, data)),
+            None => {
+                warn!("load_in_memory: unrecognized dex entry name '{}', skipping", name);
+                None
+            }
+        })
+        .collect();
+    indexed_dex_buffers.sort_by_key(|(index, _)| *index);
+    let dex_buffers: Vec<&Vec<u8>> = indexed_dex_buffers
+        .into_iter()
+        .map(|(_, data)| data)
+        .collect();
+
+    // 2. Extract Libs to Cache, ABI-preference-ordered
+    let lib_dirs = extract_native_libs_by_abi(env, cache_path, file_list);
+    let libs_dir = lib_dirs.join(":");
+    debug!("Found {} DEXs; native search path: '{}'", dex_buffers.len(), libs_dir);
+
     // 3. Create ByteBuffer[] for DEXs
     if dex_buffers.is_empty() {
         warn!("No DEX files to load in memory!");
         return Ok(());
     }
 
-    debug!("Creating ByteBuffer array for {} DEXs...", dex_buffers.len());
+    debug!("Creating direct ByteBuffer array for {} DEXs...", dex_buffers.len());
     let byte_buffer_cls = env.find_class("java/nio/ByteBuffer")?;
     let buffer_array = env.new_object_array(dex_buffers.len() as i32, &byte_buffer_cls, JObject::null())?;
 
     for (i, dex_data) in dex_buffers.iter().enumerate() {
-        debug!("Wrapping DEX {} ({} bytes)...", i, dex_data.len());
-        let byte_array = env.byte_array_from_slice(dex_data)?;
-        let buffer = env.call_static_method(
-            &byte_buffer_cls,
-            "wrap", 
-            "([B)Ljava/nio/ByteBuffer;", 
-            &[JValue::Object(&byte_array.into())]
-        )?.l()?;
-        
+        debug!("Wrapping DEX {} ({} bytes) in a direct ByteBuffer...", i, dex_data.len());
+        // ART reads straight out of this buffer rather than copying it, so the
+        // backing bytes are moved into a process-lifetime registry first.
+        let retained = retain_dex_buffer((*dex_data).clone());
+        let buffer = unsafe { env.new_direct_byte_buffer(retained)? };
         env.set_object_array_element(&buffer_array, i as i32, buffer)?;
     }
 
@@ -678,22 +920,210 @@ fn get_current_abi() -> &'static str {
     return "unknown";
 }
 
+/// Returns this device's ABI preference order (e.g. `["arm64-v8a",
+/// "armeabi-v7a"]`), primary ABI first, straight from
+/// `android.os.Build.SUPPORTED_ABIS`.
+fn get_supported_abis(env: &mut JNIEnv) -> Result<Vec<String>, jni::errors::Error> {
+    let build_cls = env.find_class("android/os/Build")?;
+    let abis_obj = env
+        .get_static_field(build_cls, "SUPPORTED_ABIS", "[Ljava/lang/String;")?
+        .l()?;
+    let abis_array: JObjectArray = abis_obj.into();
+    let len = env.get_array_length(&abis_array)?;
+
+    let mut abis = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        let elem = env.get_object_array_element(&abis_array, i)?;
+        abis.push(env.get_string(&JString::from(elem))?.into());
+    }
+    Ok(abis)
+}
+
+/// Extracts `.so` entries into one directory per ABI under
+/// `cache_path/native_libs/<abi>`, and returns those directories ordered by
+/// device ABI preference (primary ABI first), skipping any ABI that
+/// contributed no libraries. Entries for non-matching ABIs are left alone so
+/// `System.loadLibrary` can only ever resolve a compatible binary.
+fn extract_native_libs_by_abi(
+    env: &mut JNIEnv,
+    cache_path: &str,
+    file_list: &[(String, Vec<u8>)],
+) -> Vec<String> {
+    let abis = get_supported_abis(env).unwrap_or_else(|e| {
+        warn!(
+            "extract_native_libs_by_abi: Build.SUPPORTED_ABIS unavailable ({:?}), falling back to compile-time ABI",
+            e
+        );
+        vec![get_current_abi().to_string()]
+    });
+    debug!("Device ABI preference order: {:?}", abis);
+
+    let mut ordered_dirs = Vec::new();
+    for abi in &abis {
+        let lib_prefix = format!("lib/{}/", abi);
+        let abi_dir = format!("{}/native_libs/{}", cache_path, abi);
+        let mut wrote_any = false;
+
+        for (name, data) in file_list {
+            if !name.ends_with(".so") {
+                continue;
+            }
+            let filename = match name.strip_prefix(&lib_prefix) {
+                Some(filename) => filename,
+                None => continue,
+            };
+
+            if !wrote_any {
+                std::fs::create_dir_all(&abi_dir).unwrap_or(());
+            }
+            let lib_path = format!("{}/{}", abi_dir, filename);
+            debug!("Writing lib ({}): {}", abi, lib_path);
+            if let Ok(mut file) = File::create(&lib_path) {
+                let _ = file.write_all(data);
+                wrote_any = true;
+            }
+        }
+
+        if wrote_any {
+            ordered_dirs.push(abi_dir);
+        } else {
+            debug!("No libraries found for ABI '{}', skipping", abi);
+        }
+    }
+
+    ordered_dirs
+}
+
+/// Parses a multidex entry name into its load-order index: `classes.dex` is
+/// the primary dex (index 0), `classesN.dex` follows at index `N - 1`.
+/// ART resolves duplicate classes from the first dex that defines them, so
+/// this order must be preserved regardless of how the source archive
+/// enumerates entries.
+fn multidex_index(name: &str) -> Option<usize> {
+    if name == "classes.dex" {
+        return Some(0);
+    }
+    let middle = name.strip_prefix("classes")?.strip_suffix(".dex")?;
+    let n: usize = middle.parse().ok()?;
+    n.checked_sub(1)
+}
+
+fn dex_entry_name_for_index(index: usize) -> String {
+    if index == 0 {
+        "classes.dex".to_string()
+    } else {
+        format!("classes{}.dex", index + 1)
+    }
+}
+
+/// Reads the `classesN.dex` members out of an embedded jar/apk, in ascending
+/// multidex order, so a single packaged container can be treated as one
+/// logical classpath unit instead of requiring the source archive to ship
+/// pre-split dex entries.
+fn extract_nested_dex_entries(data: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut archive = ZipArchive::new(std::io::Cursor::new(data))?;
+    let mut indexed = Vec::new();
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if let Some(index) = multidex_index(entry.name()) {
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+            indexed.push((index, bytes));
+        }
+    }
+
+    indexed.sort_by_key(|(index, _)| *index);
+    Ok(indexed.into_iter().map(|(_, bytes)| bytes).collect())
+}
+
+/// Expands any `.jar`/`.apk` entries in `file_list` into their nested
+/// `classesN.dex` members, renumbered to continue after the existing
+/// top-level dex sequence, so extraction/loading can keep treating the
+/// payload as a flat list of dex and native-library entries.
+fn expand_multidex_containers(file_list: &[(String, Vec<u8>)]) -> Vec<(String, Vec<u8>)> {
+    let mut next_index = file_list
+        .iter()
+        .filter_map(|(name, _)| multidex_index(name))
+        .max()
+        .map(|index| index + 1)
+        .unwrap_or(0);
+
+    let mut expanded = Vec::with_capacity(file_list.len());
+    for (name, data) in file_list {
+        if name.ends_with(".jar") || name.ends_with(".apk") {
+            match extract_nested_dex_entries(data) {
+                Ok(nested) if !nested.is_empty() => {
+                    debug!("Unpacked {} classesN.dex from embedded container '{}'", nested.len(), name);
+                    for dex_bytes in nested {
+                        expanded.push((dex_entry_name_for_index(next_index), dex_bytes));
+                        next_index += 1;
+                    }
+                }
+                Ok(_) => warn!("expand_multidex_containers: no classesN.dex found inside embedded container '{}'", name),
+                Err(e) => warn!("expand_multidex_containers: failed to read embedded container '{}': {:?}", name, e),
+            }
+        } else {
+            expanded.push((name.clone(), data.clone()));
+        }
+    }
+
+    expanded
+}
+
+fn class_loader_simple_name(env: &mut JNIEnv, loader: &JObject) -> Result<String, jni::errors::Error> {
+    let class_obj = call_object_method(env, loader, "getClass", "()Ljava/lang/Class;")?;
+    call_string_method(env, &class_obj, "getSimpleName", "()Ljava/lang/String;")
+}
+
+fn class_loader_context_tag(simple_name: &str) -> &'static str {
+    match simple_name {
+        "DelegateLastClassLoader" => "DLC",
+        _ => "PCL",
+    }
+}
+
+/// Best-effort class-loader-context (CLC) string for `loader`, e.g.
+/// `PCL[new.dex]{PCL[]}`, describing its full parent chain so ART can match
+/// injected dex against an existing oat/vdex and skip full reverification.
+/// `dex_paths` is attributed to `loader` itself; the dex lists of ancestors
+/// are not introspected, so every ancestor has an empty path list. A CLC
+/// that is approximate or entirely unavailable only costs the reverification
+/// speedup, never correctness, so failures here should never be fatal.
+fn build_class_loader_context(
+    env: &mut JNIEnv,
+    loader: &JObject,
+    dex_paths: &str,
+) -> Result<String, jni::errors::Error> {
+    let tag = class_loader_context_tag(&class_loader_simple_name(env, loader)?);
+    let mut context = format!("{}[{}]", tag, dex_paths);
+
+    let parent = call_object_method(env, loader, "getParent", "()Ljava/lang/ClassLoader;")?;
+    if !env.is_same_object(&parent, JObject::null()) {
+        let parent_context = build_class_loader_context(env, &parent, "")?;
+        context = format!("{}{{{}}}", context, parent_context);
+    }
+
+    Ok(context)
+}
+
 fn load_file_landing(env: &mut JNIEnv, cache_path: &str, target_loader: &JObject, file_list: &[(String, Vec<u8>)]) -> Result<(), jni::errors::Error> {
     let dex_cache_dir = format!("{}/dex_landing", cache_path);
     std::fs::create_dir_all(&dex_cache_dir).unwrap_or(());
 
-    let libs_dir = format!("{}/native_libs", cache_path);
-    std::fs::create_dir_all(&libs_dir).unwrap_or(());
-
-    let mut dex_paths = Vec::new();
+    let mut indexed_dex_paths = Vec::new();
 
-    // 2. Extract Files
-    let current_abi = get_current_abi();
-    let lib_prefix = format!("lib/{}/", current_abi);
-
-    for (i, (name, data)) in file_list.iter().enumerate() {
+    // 2. Extract dex files
+    for (name, data) in file_list.iter() {
         if name.ends_with(".dex") {
-             let dex_path = format!("{}/payload_{}.dex", dex_cache_dir, i);
+             let index = match multidex_index(name) {
+                 Some(index) => index,
+                 None => {
+                     warn!("load_file_landing: unrecognized dex entry name '{}', skipping", name);
+                     continue;
+                 }
+             };
+             let dex_path = format!("{}/classes{}.dex", dex_cache_dir, index + 1);
              if let Ok(mut file) = File::create(&dex_path) {
                 let _ = file.write_all(data);
                 #[cfg(unix)]
@@ -706,16 +1136,28 @@ fn load_file_landing(env: &mut JNIEnv, cache_path: &str, target_loader: &JObject
                     }
                 }
              }
-             dex_paths.push(dex_path);
-        } else if name.starts_with(&lib_prefix) && name.ends_with(".so") {
-             let filename = name.strip_prefix(&lib_prefix).unwrap_or(name);
-             let lib_path = format!("{}/{}", libs_dir, filename);
-             if let Ok(mut file) = File::create(&lib_path) {
-                let _ = file.write_all(data);
-             }
+             indexed_dex_paths.push((index, dex_path));
         }
     }
-    
+
+    // Extract native libraries, ABI-preference-ordered
+    let lib_dirs = extract_native_libs_by_abi(env, cache_path, file_list);
+
+    // Load order must be classes.dex, classes2.dex, classes3.dex, ... in
+    // ascending index regardless of archive iteration order.
+    indexed_dex_paths.sort_by_key(|(index, _)| *index);
+    for window in indexed_dex_paths.windows(2) {
+        let (prev, _) = window[0];
+        let (next, _) = window[1];
+        if next != prev + 1 {
+            warn!(
+                "load_file_landing: gap in multidex sequence between classes{}.dex and classes{}.dex",
+                prev + 1, next + 1
+            );
+        }
+    }
+
+    let dex_paths: Vec<String> = indexed_dex_paths.into_iter().map(|(_, path)| path).collect();
     let joined_paths = dex_paths.join(":");
 
     if joined_paths.is_empty() {
@@ -728,33 +1170,65 @@ fn load_file_landing(env: &mut JNIEnv, cache_path: &str, target_loader: &JObject
     let dex_path_j = env.new_string(&joined_paths)?;
     let dex_path_obj: JObject = dex_path_j.into();
 
-    let add_dex_result = env.call_method(
-        target_loader,
-        "addDexPath",
-        "(Ljava/lang/String;Z)V",
-        &[JValue::Object(&dex_path_obj), JValue::Bool(0)],
-    );
-    if add_dex_result.is_err() {
-        let _ = env.exception_clear();
-        env.call_method(
+    // Try the context-aware entry point first: supplying the class-loader
+    // context lets ART reuse an existing oat/vdex for this dex instead of
+    // forcing full reverification on every load. Older runtimes don't expose
+    // it, so fall back to the plain overloads below on any failure.
+    let mut added = false;
+    match build_class_loader_context(env, target_loader, &joined_paths) {
+        Ok(clc) => {
+            debug!("load_file_landing: class-loader context: {}", clc);
+            let clc_j = env.new_string(&clc)?;
+            let clc_obj: JObject = clc_j.into();
+            let add_with_context = env.call_method(
+                target_loader,
+                "addDexPath",
+                "(Ljava/lang/String;Ljava/lang/String;Z)V",
+                &[JValue::Object(&dex_path_obj), JValue::Object(&clc_obj), JValue::Bool(0)],
+            );
+            if add_with_context.is_ok() {
+                added = true;
+            } else {
+                let _ = env.exception_clear();
+            }
+        }
+        Err(e) => {
+            debug!("load_file_landing: could not build class-loader context, skipping: {:?}", e);
+        }
+    }
+
+    if !added {
+        let add_dex_result = env.call_method(
             target_loader,
             "addDexPath",
-            "(Ljava/lang/String;)V",
-            &[JValue::Object(&dex_path_obj)],
-        )?;
+            "(Ljava/lang/String;Z)V",
+            &[JValue::Object(&dex_path_obj), JValue::Bool(0)],
+        );
+        if add_dex_result.is_err() {
+            let _ = env.exception_clear();
+            env.call_method(
+                target_loader,
+                "addDexPath",
+                "(Ljava/lang/String;)V",
+                &[JValue::Object(&dex_path_obj)],
+            )?;
+        }
     }
 
-    // 4. Best-effort add native lib search path for extracted .so files
-    let libs_dir_j = env.new_string(&libs_dir)?;
-    let libs_dir_obj: JObject = libs_dir_j.into();
+    // 4. Best-effort add native lib search path for extracted .so files, primary
+    // ABI directory first so System.loadLibrary prefers it over any fallback ABI.
     let array_list_cls = env.find_class("java/util/ArrayList")?;
     let native_paths = env.new_object(&array_list_cls, "()V", &[])?;
-    env.call_method(
-        &native_paths,
-        "add",
-        "(Ljava/lang/Object;)Z",
-        &[JValue::Object(&libs_dir_obj)],
-    )?;
+    for lib_dir in &lib_dirs {
+        let lib_dir_j = env.new_string(lib_dir)?;
+        let lib_dir_obj: JObject = lib_dir_j.into();
+        env.call_method(
+            &native_paths,
+            "add",
+            "(Ljava/lang/Object;)Z",
+            &[JValue::Object(&lib_dir_obj)],
+        )?;
+    }
 
     let add_native_result = env.call_method(
         target_loader,
@@ -1,18 +1,64 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce,
+    aead::{generic_array::GenericArray, stream::EncryptorBE32, KeyInit},
+    Aes256Gcm,
 };
-use clap::Parser;
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::{Parser, ValueEnum};
+use hkdf::Hkdf;
+use memmap2::Mmap;
 use rand::Rng;
+use sha2::Sha256;
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 mod config;
 use config::AES_KEY;
 
+/// AEAD cipher used to encrypt payload entries. Both variants are 256-bit-key,
+/// 12-byte-nonce AEADs, so the per-entry nonce layout is identical either way;
+/// only the one-byte id recorded in the blob trailer tells the loader which
+/// one to use.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Cipher {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "chacha20-poly1305")]
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 0,
+            Cipher::Chacha20Poly1305 => 1,
+        }
+    }
+}
+
+/// Compression applied to a payload entry's plaintext before encryption. The
+/// method actually used per entry is recorded next to it in the blob
+/// metadata (`0 = none`), since compression is skipped whenever it doesn't
+/// actually shrink the entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum PayloadCompression {
+    None,
+    Xz,
+    Zstd,
+}
+
+impl PayloadCompression {
+    fn id(self) -> u8 {
+        match self {
+            PayloadCompression::None => 0,
+            PayloadCompression::Xz => 1,
+            PayloadCompression::Zstd => 2,
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -42,6 +88,12 @@ struct Args {
 
     #[arg(long)]
     resources: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+    cipher: Cipher,
+
+    #[arg(long, value_enum, default_value_t = PayloadCompression::None)]
+    compress: PayloadCompression,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -74,25 +126,45 @@ fn main() -> anyhow::Result<()> {
     println!("Keep prefixes: {:?}", keep_prefixes);
     println!("Keep libs: {:?}", keep_libs);
 
-    let payload_entries = collect_and_encrypt_payload_entries(&args.target, &keep_descriptors, &keep_prefixes, &keep_libs)?;
-    let encrypted_entry_names: HashSet<String> = payload_entries
-        .iter()
-        .map(|(name, _, _)| name.clone())
-        .collect();
-    if payload_entries.is_empty() {
+    println!("Cipher: {:?}", args.cipher);
+    println!("Compression: {:?}", args.compress);
+
+    let mut build_salt = [0u8; 16];
+    rand::thread_rng().fill(&mut build_salt);
+
+    let target_file = File::open(&args.target)?;
+    // SAFETY: the target APK is not expected to be modified by another process
+    // while packing runs. Memory-mapping it lets every payload entry (some of
+    // them multi-hundred-MB native libraries) be sliced and streamed straight
+    // out of the file's page cache instead of read_to_end'd onto the heap.
+    let target_mmap = unsafe { Mmap::map(&target_file)? };
+    let mut target_zip = ZipArchive::new(std::io::Cursor::new(&target_mmap[..]))?;
+
+    let payload_plans = plan_payload_entries(
+        &mut target_zip,
+        &keep_descriptors,
+        &keep_prefixes,
+        &keep_libs,
+        args.compress,
+    )?;
+    if payload_plans.is_empty() {
         anyhow::bail!("No classes*.dex or lib/**/*.so found in target APK");
     }
+    let encrypted_entry_names: HashSet<String> =
+        payload_plans.iter().map(|plan| plan.name.clone()).collect();
 
-    let payload_blob = build_payload_blob(&payload_entries);
     repack_target_with_bootstrap(
-        &args.target,
+        &mut target_zip,
         &args.bootstrap_apk,
         &args.bootstrap_lib_dir,
         args.patched_manifest.as_deref(),
         args.resources.as_deref(),
         &encrypted_entry_names,
+        payload_plans,
+        args.cipher,
+        &build_salt,
+        AES_KEY,
         &args.output,
-        &payload_blob,
     )?;
 
     println!("Success! Output written to {}", args.output.display());
@@ -158,7 +230,7 @@ fn should_keep_lib(name: &str, keep_libs: &[String]) -> bool {
     let filename = Path::new(name).file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("");
-    
+
     for kept in keep_libs {
         if filename == kept || filename == format!("lib{}.so", kept) || filename == format!("{}.so", kept) {
             return true;
@@ -167,88 +239,312 @@ fn should_keep_lib(name: &str, keep_libs: &[String]) -> bool {
     false
 }
 
-fn collect_and_encrypt_payload_entries(
-    target_apk: &Path,
+/// Derives a per-entry subkey from the build's master key so a single large
+/// APK does not encrypt every dex/so under one shared key: `PRK =
+/// HKDF-Extract(salt, master_key)`, then `entry_key = HKDF-Expand(PRK, info =
+/// entry_name, L = 32)`. The loader re-derives the same key from the salt
+/// (carried in the blob trailer) and the entry name already present in the
+/// metadata, so nothing extra needs to travel with the ciphertext.
+fn derive_entry_key(salt: &[u8; 16], master_key: &[u8; 32], entry_name: &str) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), master_key);
+    let mut entry_key = [0u8; 32];
+    hkdf.expand(entry_name.as_bytes(), &mut entry_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    entry_key
+}
+
+/// Derives the key used to BLAKE3-keyed-hash the blob's ciphertext-plus-metadata
+/// buffer, distinct from any per-entry key so the integrity trailer can't be
+/// forged from knowledge of an individual entry's key.
+fn derive_integrity_key(salt: &[u8; 16], master_key: &[u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), master_key);
+    let mut integrity_key = [0u8; 32];
+    hkdf.expand(b"blob-integrity", &mut integrity_key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    integrity_key
+}
+
+/// Plaintext chunk size for the STREAM AEAD construction (see
+/// `StreamEncryptor`). Each chunk is encrypted independently, so streaming an
+/// uncompressed entry into the payload buffers at most this many bytes of
+/// plaintext at once, regardless of the entry's total size. A compressed
+/// entry is held whole in memory (see `compress_entry`), and the loader
+/// accumulates each entry's decrypted plaintext in full.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Authentication tag length appended to every chunk by both AES-256-GCM and
+/// ChaCha20-Poly1305.
+const STREAM_TAG_LEN: usize = 16;
+
+fn random_nonce_prefix() -> [u8; 7] {
+    let mut prefix = [0u8; 7];
+    rand::thread_rng().fill(&mut prefix);
+    prefix
+}
+
+/// STREAM-construction (Rogaway) encryptor over either selectable cipher:
+/// the 12-byte AEAD nonce is split into a 7-byte per-entry prefix (generated
+/// once and stored in the metadata) plus an internal 32-bit big-endian chunk
+/// counter and a 1-byte "is this the last chunk" flag, so no two chunks
+/// across the whole build ever reuse a nonce under the same entry key.
+enum StreamEncryptor {
+    Aes256Gcm(EncryptorBE32<Aes256Gcm>),
+    Chacha20Poly1305(EncryptorBE32<ChaCha20Poly1305>),
+}
+
+impl StreamEncryptor {
+    fn new(cipher: Cipher, entry_key: &[u8; 32], nonce_prefix: &[u8; 7]) -> Self {
+        let nonce_prefix = GenericArray::from_slice(nonce_prefix);
+        match cipher {
+            Cipher::Aes256Gcm => StreamEncryptor::Aes256Gcm(EncryptorBE32::from_aead(
+                Aes256Gcm::new(&(*entry_key).into()),
+                nonce_prefix,
+            )),
+            Cipher::Chacha20Poly1305 => StreamEncryptor::Chacha20Poly1305(EncryptorBE32::from_aead(
+                ChaCha20Poly1305::new(&(*entry_key).into()),
+                nonce_prefix,
+            )),
+        }
+    }
+
+    fn encrypt_next(&mut self, chunk: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            StreamEncryptor::Aes256Gcm(enc) => enc
+                .encrypt_next(chunk)
+                .map_err(|e| anyhow::anyhow!("stream encryption failure: {:?}", e)),
+            StreamEncryptor::Chacha20Poly1305(enc) => enc
+                .encrypt_next(chunk)
+                .map_err(|e| anyhow::anyhow!("stream encryption failure: {:?}", e)),
+        }
+    }
+
+    fn encrypt_last(self, chunk: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            StreamEncryptor::Aes256Gcm(enc) => enc
+                .encrypt_last(chunk)
+                .map_err(|e| anyhow::anyhow!("stream encryption failure: {:?}", e)),
+            StreamEncryptor::Chacha20Poly1305(enc) => enc
+                .encrypt_last(chunk)
+                .map_err(|e| anyhow::anyhow!("stream encryption failure: {:?}", e)),
+        }
+    }
+}
+
+/// Buffers incoming plaintext (or compressor output) into `STREAM_CHUNK_SIZE`
+/// chunks and pushes each full chunk through `StreamEncryptor` and straight
+/// into the output zip entry as it fills, updating the running integrity
+/// hash alongside it. `finish` flushes whatever partial chunk remains as the
+/// STREAM construction's final block. The sink itself buffers at most one
+/// chunk per entry no matter how large the entry is.
+struct ChunkSink<'w> {
+    encryptor: Option<StreamEncryptor>,
+    buf: Vec<u8>,
+    chunk_count: u32,
+    plaintext_len: u64,
+    writer: &'w mut ZipWriter<File>,
+    hasher: &'w mut blake3::Hasher,
+}
+
+impl<'w> ChunkSink<'w> {
+    fn new(
+        cipher: Cipher,
+        entry_key: [u8; 32],
+        nonce_prefix: [u8; 7],
+        writer: &'w mut ZipWriter<File>,
+        hasher: &'w mut blake3::Hasher,
+    ) -> Self {
+        ChunkSink {
+            encryptor: Some(StreamEncryptor::new(cipher, &entry_key, &nonce_prefix)),
+            buf: Vec::with_capacity(STREAM_CHUNK_SIZE),
+            chunk_count: 0,
+            plaintext_len: 0,
+            writer,
+            hasher,
+        }
+    }
+
+    fn emit_full_chunks(&mut self) -> anyhow::Result<()> {
+        while self.buf.len() >= STREAM_CHUNK_SIZE {
+            let rest = self.buf.split_off(STREAM_CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            let ciphertext = self
+                .encryptor
+                .as_mut()
+                .expect("encrypt_next called after finish")
+                .encrypt_next(&chunk)?;
+            self.writer.write_all(&ciphertext)?;
+            self.hasher.update(&ciphertext);
+            self.chunk_count += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<(u32, u64)> {
+        let remainder = std::mem::take(&mut self.buf);
+        let ciphertext = self
+            .encryptor
+            .take()
+            .expect("finish consumes the sink")
+            .encrypt_last(&remainder)?;
+        self.writer.write_all(&ciphertext)?;
+        self.hasher.update(&ciphertext);
+        self.chunk_count += 1;
+        Ok((self.chunk_count, self.plaintext_len))
+    }
+}
+
+impl<'w> Write for ChunkSink<'w> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.plaintext_len += data.len() as u64;
+        self.buf.extend_from_slice(data);
+        self.emit_full_chunks()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compresses `reader`'s full contents with `compression` into memory, so the
+/// result can be measured against the entry's original size before deciding
+/// whether to keep it (see the call site in `repack_target_with_bootstrap`).
+fn compress_entry(reader: &mut impl Read, compression: PayloadCompression) -> anyhow::Result<Vec<u8>> {
+    match compression {
+        PayloadCompression::None => unreachable!("None never needs a compressed candidate"),
+        PayloadCompression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+            std::io::copy(reader, &mut encoder)?;
+            Ok(encoder.finish()?)
+        }
+        PayloadCompression::Zstd => Ok(zstd::stream::encode_all(reader, 0)?),
+    }
+}
+
+/// Reads `reader` in `STREAM_CHUNK_SIZE`-sized bursts into `sink` and
+/// finishes it. Used both for an uncompressed entry, read straight from the
+/// archive, and for a compressed entry, read from its in-memory buffer.
+fn stream_into_sink(reader: &mut impl Read, mut sink: ChunkSink) -> anyhow::Result<(u32, u64)> {
+    let mut buf = [0u8; STREAM_CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        sink.write_all(&buf[..n])?;
+    }
+    sink.finish()
+}
+
+struct PayloadEntryPlan {
+    zip_index: usize,
+    name: String,
+    compression: PayloadCompression,
+}
+
+/// Decides which dex/so entries in the target APK get encrypted into the
+/// payload and which stay in plaintext, without reading any entry's full
+/// bytes except the dex files that need scanning for a keep-class/prefix
+/// match. Native libraries, which are the entries actually large enough to
+/// matter for memory, are decided on their name alone.
+fn plan_payload_entries<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
     keep_descriptors: &[String],
     keep_prefixes: &[String],
     keep_libs: &[String],
-) -> anyhow::Result<Vec<(String, Vec<u8>, [u8; 12])>> {
-    let target_file = File::open(target_apk)?;
-    let mut zip = ZipArchive::new(target_file)?;
-
-    let mut entries: Vec<(String, Vec<u8>, [u8; 12])> = Vec::new();
+    compress: PayloadCompression,
+) -> anyhow::Result<Vec<PayloadEntryPlan>> {
+    let mut plans = Vec::new();
 
     for i in 0..zip.len() {
         let file = zip.by_index(i)?;
         let name = file.name().to_string();
 
-        if is_payload_entry(&name) {
+        if !is_payload_entry(&name) {
+            continue;
+        }
+
+        if name.ends_with(".dex") {
             drop(file);
             let mut file = zip.by_index(i)?;
             let mut buffer = Vec::new();
             file.read_to_end(&mut buffer)?;
 
-            if name.ends_with(".dex")
-                && (should_keep_dex(&name, &buffer, keep_descriptors)
-                    || matches_keep_prefix(&buffer, keep_prefixes))
+            if should_keep_dex(&name, &buffer, keep_descriptors)
+                || matches_keep_prefix(&buffer, keep_prefixes)
             {
                 println!("Keeping {} in plaintext for startup compatibility", name);
                 continue;
             }
-
-            if name.ends_with(".so") && should_keep_lib(&name, keep_libs) {
-                println!("Keeping {} in plaintext for startup compatibility", name);
-                continue;
-            }
-
-            println!("Encrypting {}...", name);
-            let (encrypted, nonce) = encrypt_payload(&buffer)?;
-            entries.push((name, encrypted, nonce));
+        } else if should_keep_lib(&name, keep_libs) {
+            println!("Keeping {} in plaintext for startup compatibility", name);
+            continue;
         }
+
+        plans.push(PayloadEntryPlan {
+            zip_index: i,
+            name,
+            compression: compress,
+        });
     }
 
-    println!("Encrypted {} entries total", entries.len());
-    Ok(entries)
+    Ok(plans)
 }
 
-fn build_payload_blob(entries: &[(String, Vec<u8>, [u8; 12])]) -> Vec<u8> {
-    let mut payload_blob = Vec::new();
-
-    for (_, enc_data, _) in entries {
-        payload_blob.extend_from_slice(enc_data);
-    }
+struct PayloadEntryMeta {
+    name: String,
+    offset: u64,
+    plaintext_size: u64,
+    chunk_size: u32,
+    chunk_count: u32,
+    nonce_prefix: [u8; 7],
+    compression_id: u8,
+}
 
+/// Blob layout version. Bumped whenever the footer or metadata record shape
+/// changes, so an older loader fails the version check instead of
+/// misparsing a newer blob. See the loader's matching `PAYLOAD_FORMAT_VERSION`.
+const PAYLOAD_FORMAT_VERSION: u8 = 4;
+
+/// Builds the payload metadata table: `[N (4)]` followed by, per entry,
+/// `[NameLen(2)] [Name] [Offset(8)] [PlaintextSize(8)] [ChunkSize(4)]
+/// [ChunkCount(4)] [NoncePrefix(7)] [Compression(1)]`. The offset is
+/// absolute into the ciphertext region (byte 0 of the payload entry), so the
+/// loader can seek straight to any entry's first chunk without summing the
+/// encrypted sizes of the entries before it.
+fn build_payload_metadata(entries: &[PayloadEntryMeta]) -> Vec<u8> {
     let mut metadata = Vec::new();
     metadata.extend_from_slice(&(entries.len() as u32).to_le_bytes());
 
-    for (name, enc_data, nonce) in entries {
-        let name_bytes = name.as_bytes();
+    for entry in entries {
+        let name_bytes = entry.name.as_bytes();
         metadata.extend_from_slice(&(name_bytes.len() as u16).to_le_bytes());
         metadata.extend_from_slice(name_bytes);
-        metadata.extend_from_slice(&(enc_data.len() as u32).to_le_bytes());
-        metadata.extend_from_slice(nonce);
+        metadata.extend_from_slice(&entry.offset.to_le_bytes());
+        metadata.extend_from_slice(&entry.plaintext_size.to_le_bytes());
+        metadata.extend_from_slice(&entry.chunk_size.to_le_bytes());
+        metadata.extend_from_slice(&entry.chunk_count.to_le_bytes());
+        metadata.extend_from_slice(&entry.nonce_prefix);
+        metadata.push(entry.compression_id);
     }
 
-    payload_blob.extend_from_slice(&metadata);
-    payload_blob.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
-    payload_blob.extend_from_slice(b"SHELL");
-
-    payload_blob
+    metadata
 }
 
-fn repack_target_with_bootstrap(
-    target_apk: &Path,
+fn repack_target_with_bootstrap<R: Read + Seek>(
+    target_zip: &mut ZipArchive<R>,
     bootstrap_apk: &Path,
     bootstrap_lib_dir: &Path,
     patched_manifest: Option<&Path>,
     resources_arsc: Option<&Path>,
     encrypted_entry_names: &HashSet<String>,
+    payload_plans: Vec<PayloadEntryPlan>,
+    cipher: Cipher,
+    build_salt: &[u8; 16],
+    master_key: &[u8; 32],
     output_apk: &Path,
-    payload_blob: &[u8],
 ) -> anyhow::Result<()> {
-    let target_file = File::open(target_apk)?;
-    let mut target_zip = ZipArchive::new(target_file)?;
-
     let output_file = File::create(output_apk)?;
     let mut writer = ZipWriter::new(output_file);
 
@@ -268,7 +564,10 @@ fn repack_target_with_bootstrap(
         None
     };
 
-    let mut retained_dex_entries: Vec<(usize, Vec<u8>)> = Vec::new();
+    // (dex index, zip index) pairs only, so sorting into canonical multidex
+    // order doesn't require holding every retained dex's bytes in memory at
+    // once; each is streamed from the archive once its final position is known.
+    let mut retained_dex_entries: Vec<(usize, usize)> = Vec::new();
 
     for i in 0..target_zip.len() {
         let mut file = target_zip.by_index(i)?;
@@ -299,13 +598,9 @@ fn repack_target_with_bootstrap(
         let options = FileOptions::default().compression_method(file.compression());
         if file.is_dir() {
             writer.add_directory(name, options)?;
+        } else if let Some(index) = class_index(&name) {
+            retained_dex_entries.push((index, i));
         } else {
-            if let Some(index) = class_index(&name) {
-                let mut dex_bytes = Vec::new();
-                file.read_to_end(&mut dex_bytes)?;
-                retained_dex_entries.push((index, dex_bytes));
-                continue;
-            }
             writer.start_file(name, options)?;
             std::io::copy(&mut file, &mut writer)?;
         }
@@ -316,7 +611,7 @@ fn repack_target_with_bootstrap(
     // 1. Inject Bootstrap DEX as the FIRST dex file(s)
     let bootstrap_dex_entries = get_bootstrap_dex_entries(bootstrap_apk)?;
     let num_bootstrap_dexes = bootstrap_dex_entries.len();
-    
+
     let dex_options = FileOptions::default().compression_method(CompressionMethod::Stored);
     for (i, dex_bytes) in bootstrap_dex_entries.into_iter().enumerate() {
         let dex_name = dex_name_for_index(i + 1);
@@ -324,20 +619,95 @@ fn repack_target_with_bootstrap(
         writer.write_all(&dex_bytes)?;
     }
 
-    // 2. Write Retained DEXs starting from the next index
-    for (i, (_, dex_bytes)) in retained_dex_entries.iter().enumerate() {
+    // 2. Write Retained DEXs starting from the next index, streamed straight
+    // out of the target archive now that their final order is decided.
+    for (i, (_, zip_index)) in retained_dex_entries.iter().enumerate() {
+        let mut file = target_zip.by_index(*zip_index)?;
         let dex_name = dex_name_for_index(num_bootstrap_dexes + i + 1);
         writer.start_file(dex_name, dex_options)?;
-        writer.write_all(dex_bytes)?;
+        std::io::copy(&mut file, &mut writer)?;
     }
 
     inject_bootstrap_libs(bootstrap_lib_dir, &mut writer)?;
 
+    // 3. Stream-encrypt every payload entry straight into the payload zip
+    // entry, one STREAM_CHUNK_SIZE chunk at a time.
     writer.start_file(
         "assets/kapp_payload.bin",
         FileOptions::default().compression_method(CompressionMethod::Stored),
     )?;
-    writer.write_all(payload_blob)?;
+
+    let integrity_key = derive_integrity_key(build_salt, master_key);
+    let mut hasher = blake3::Hasher::new_keyed(&integrity_key);
+    let mut entry_metas = Vec::with_capacity(payload_plans.len());
+    let mut offset: u64 = 0;
+
+    for plan in payload_plans {
+        // Compression is only worth the metadata bit (and the decompression cost on
+        // load) when it actually shrinks the entry, so try it first and compare
+        // against the entry's original size before committing to it below.
+        let original_size = target_zip.by_index(plan.zip_index)?.size();
+        let compressed = if plan.compression == PayloadCompression::None {
+            None
+        } else {
+            let mut reader = target_zip.by_index(plan.zip_index)?;
+            let candidate = compress_entry(&mut reader, plan.compression)?;
+            if (candidate.len() as u64) < original_size {
+                Some(candidate)
+            } else {
+                None
+            }
+        };
+        let applied_compression = match &compressed {
+            Some(_) => plan.compression,
+            None => PayloadCompression::None,
+        };
+
+        println!("Encrypting {} (compression: {:?})...", plan.name, applied_compression);
+
+        let entry_key = derive_entry_key(build_salt, master_key, &plan.name);
+        let nonce_prefix = random_nonce_prefix();
+        let sink = ChunkSink::new(cipher, entry_key, nonce_prefix, &mut writer, &mut hasher);
+
+        let (chunk_count, plaintext_size) = match compressed {
+            Some(buf) => stream_into_sink(&mut std::io::Cursor::new(buf), sink)?,
+            None => stream_into_sink(&mut target_zip.by_index(plan.zip_index)?, sink)?,
+        };
+
+        entry_metas.push(PayloadEntryMeta {
+            name: plan.name,
+            offset,
+            plaintext_size,
+            chunk_size: STREAM_CHUNK_SIZE as u32,
+            chunk_count,
+            nonce_prefix,
+            compression_id: applied_compression.id(),
+        });
+        offset += plaintext_size + STREAM_TAG_LEN as u64 * chunk_count as u64;
+    }
+
+    println!("Encrypted {} entries total", entry_metas.len());
+
+    // The digest covers the ciphertext region, the metadata table, and the
+    // metadata size (everything written to the payload entry so far), so a
+    // reordered entry, renamed entry, or truncated metadata table changes the
+    // digest even though every individual chunk's own AEAD tag still checks out.
+    let metadata = build_payload_metadata(&entry_metas);
+    writer.write_all(&metadata)?;
+    hasher.update(&metadata);
+
+    let metadata_size = (metadata.len() as u32).to_le_bytes();
+    writer.write_all(&metadata_size)?;
+    hasher.update(&metadata_size);
+
+    let digest = *hasher.finalize().as_bytes();
+
+    // Footer: [Metadata Size (4)] [HKDF Salt (16)] [Cipher Id (1)] [Format Version (1)]
+    //         [BLAKE3 Digest (32)] [Magic "SHELL" (5)]
+    writer.write_all(build_salt)?;
+    writer.write_all(&[cipher.id(), PAYLOAD_FORMAT_VERSION])?;
+    writer.write_all(&digest)?;
+    writer.write_all(b"SHELL")?;
 
     writer.finish()?;
     Ok(())
@@ -407,18 +777,3 @@ fn inject_bootstrap_libs(bootstrap_lib_dir: &Path, writer: &mut ZipWriter<File>)
 
     Ok(())
 }
-
-fn encrypt_payload(data: &[u8]) -> anyhow::Result<(Vec<u8>, [u8; 12])> {
-    let key = *AES_KEY;
-    let cipher = Aes256Gcm::new(&key.into());
-
-    let mut nonce_bytes = [0u8; 12];
-    rand::thread_rng().fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    let ciphertext = cipher
-        .encrypt(nonce, data)
-        .map_err(|e| anyhow::anyhow!("Encryption failure: {:?}", e))?;
-
-    Ok((ciphertext, nonce_bytes))
-}